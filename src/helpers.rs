@@ -1,40 +1,112 @@
-use super::PpmLoadResult;
+use super::{PpmLoadResult, PpmLoadError, PpmPixel};
+
+
+/// Rejects any sample greater than `depth`, the maxval declared in the
+/// header — used for `P3`, whose samples are plain decimal text with no
+/// inherent ceiling. The raw formats enforce this at the byte level in
+/// `parse::RawSampleStream` instead.
+pub struct BoundedValues<I> where I: Iterator<Item=PpmLoadResult<u32>> {
+    iterator: I,
+    depth: u32,
+}
+
+
+impl<I> Iterator for BoundedValues<I> where I: Iterator<Item=PpmLoadResult<u32>> {
+    type Item = PpmLoadResult<u32>;
+
+    fn next(&mut self) -> Option<PpmLoadResult<u32>> {
+        match self.iterator.next() {
+            Some(Ok(value)) => {
+                if value > self.depth {
+                    Some(Err(PpmLoadError::FormatError))
+                } else {
+                    Some(Ok(value))
+                }
+            },
+            other => other,
+        }
+    }
+}
+
+
+pub fn bounded<I: Iterator<Item=PpmLoadResult<u32>>>(iterator: I, depth: u32) -> BoundedValues<I> {
+    BoundedValues {
+        iterator: iterator,
+        depth: depth,
+    }
+}
+
+
+/// Rescales every channel of every pixel from `old_max` to `new_max`:
+/// `sample * new_max / old_max`. Used to normalize pixels to a
+/// caller-requested depth regardless of the source file's declared maxval.
+pub struct RescaledPixels<I> where I: Iterator<Item=PpmLoadResult<PpmPixel>> {
+    iterator: I,
+    old_max: u32,
+    new_max: u32,
+}
+
+
+impl<I> Iterator for RescaledPixels<I> where I: Iterator<Item=PpmLoadResult<PpmPixel>> {
+    type Item = PpmLoadResult<PpmPixel>;
+
+    fn next(&mut self) -> Option<PpmLoadResult<PpmPixel>> {
+        match self.iterator.next() {
+            Some(Ok(pixel)) => {
+                let channels = pixel.channels().iter()
+                    .map(|&value| rescale_sample(value, self.old_max, self.new_max))
+                    .collect();
+                Some(Ok(PpmPixel::new(channels)))
+            },
+            other => other,
+        }
+    }
+}
+
+
+pub fn rescale<I: Iterator<Item=PpmLoadResult<PpmPixel>>>(iterator: I, old_max: u32, new_max: u32) -> RescaledPixels<I> {
+    RescaledPixels {
+        iterator: iterator,
+        old_max: old_max,
+        new_max: new_max,
+    }
+}
+
+
+fn rescale_sample(value: u32, old_max: u32, new_max: u32) -> u32 {
+    if old_max == 0 {
+        return 0;
+    }
+    ((value as u64) * (new_max as u64) / (old_max as u64)) as u32
+}
 
 
 pub struct PpmPixelChunks<I> where I: Iterator<Item=PpmLoadResult<u32>> {
     iterator: I,
-    cur: usize,
-    state: [u32; 3],
+    samples_per_pixel: usize,
 }
 
 
 impl<I> Iterator for PpmPixelChunks<I> where I: Iterator<Item=PpmLoadResult<u32>> {
-    type Item = PpmLoadResult<[u32; 3]>;
+    type Item = PpmLoadResult<PpmPixel>;
 
-    fn next(&mut self) -> Option<PpmLoadResult<[u32; 3]>> {
-        while self.cur < self.state.len() {
+    fn next(&mut self) -> Option<PpmLoadResult<PpmPixel>> {
+        let mut channels = Vec::with_capacity(self.samples_per_pixel);
+        while channels.len() < self.samples_per_pixel {
             match self.iterator.next() {
-                Some(Ok(t)) => {
-                    self.state[self.cur] = t;
-                    self.cur += 1;
-                },
+                Some(Ok(value)) => channels.push(value),
                 Some(Err(err)) => return Some(Err(err)),
                 None => return None,
             }
         }
-        let retval = Some(Ok(self.state));
-        self.cur = 0;
-        self.state = [0, 0, 0];
-        retval
+        Some(Ok(PpmPixel::new(channels)))
     }
 }
 
 
-pub fn chunks<I: Iterator<Item=PpmLoadResult<u32>>>(iterator: I) -> PpmPixelChunks<I> {
+pub fn chunks<I: Iterator<Item=PpmLoadResult<u32>>>(iterator: I, samples_per_pixel: usize) -> PpmPixelChunks<I> {
     PpmPixelChunks {
         iterator: iterator,
-        cur: 0,
-        state: [0, 0, 0],
+        samples_per_pixel: samples_per_pixel,
     }
 }
-
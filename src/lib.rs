@@ -5,6 +5,9 @@ use std::path::Path;
 
 mod parse;
 mod helpers;
+mod write;
+
+pub use write::{ToPpm, write_ppm, write_ppm_ascii, write_ppm_raw, DEFAULT_MAX_LINE_WIDTH};
 
 
 #[derive(Debug)]
@@ -27,48 +30,262 @@ impl From<io::Error> for PpmLoadError {
 pub type PpmLoadResult<T> = Result<T, PpmLoadError>;
 
 
-#[derive(PartialEq, Clone, Copy, Debug)]
-pub struct PpmPixel(pub u32, pub u32, pub u32);
+/// A pixel with however many samples its source format carries: three for
+/// RGB (`P3`/`P6`), one for grayscale (`P5`) or bitmap (`P4`).
+#[derive(PartialEq, Clone, Debug)]
+pub struct PpmPixel {
+    channels: Vec<u32>,
+}
+
+
+impl PpmPixel {
+    pub fn new(channels: Vec<u32>) -> PpmPixel {
+        PpmPixel { channels: channels }
+    }
+
+    pub fn channels(&self) -> &[u32] {
+        &self.channels
+    }
+}
 
 
 pub trait FromPpm {
-    fn from_ppm(width: u32, height: u32, depth: u32,
+    fn from_ppm(width: u32, height: u32, depth: u32, channels: usize,
                 pixels: &mut Iterator<Item=PpmLoadResult<PpmPixel>>
                ) -> PpmLoadResult<Self>;
 }
 
 
-pub fn read_ppm<R, T>(mut reader: R) -> Result<T, PpmLoadError>
-    where
-        R: Read,
-        T: FromPpm {
-    
-    // TODO(sell): Is this OK?
-    let mut header: [u8; 3] = [0, 0, 0];
-    let header_read = try!(reader.read(&mut header));
-    if header_read != 3 {
-        return Err(PpmLoadError::Truncated);
+/// A decoded sample value; an alias rather than a newtype so that header
+/// sizing and buffer-filling code can talk about "how many samples" without
+/// pulling in the `PpmPixel` grouping.
+pub type Sample = u32;
+
+
+/// Which Netpbm encoding a stream uses.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum PpmFormat {
+    /// `P3`: ASCII decimal RGB.
+    AsciiColor,
+    /// `P6`: rawbits RGB.
+    RawColor,
+    /// `P5`: rawbits grayscale.
+    RawGray,
+    /// `P4`: rawbits bitmap.
+    RawBitmap,
+}
+
+
+impl PpmFormat {
+    pub fn channels(&self) -> usize {
+        match *self {
+            PpmFormat::AsciiColor | PpmFormat::RawColor => 3,
+            PpmFormat::RawGray | PpmFormat::RawBitmap => 1,
+        }
     }
+}
+
+
+/// The decoded header of a Netpbm stream: everything needed to size a
+/// buffer before reading a single sample of pixel data.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct PpmHeader {
+    pub format: PpmFormat,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+
+impl PpmHeader {
+    pub fn channels(&self) -> usize {
+        self.format.channels()
+    }
+
+    /// How many `Sample`s the raster holds: `width * height * channels`.
+    pub fn sample_count(&self) -> usize {
+        (self.width as usize) * (self.height as usize) * self.channels()
+    }
+
+    /// How many bytes of raw sample data follow the header on disk. Only
+    /// meaningful for the binary formats (`P4`/`P5`/`P6`); `P3`'s raster is
+    /// textual, so there's no fixed byte count to report.
+    pub fn required_bytes(&self) -> usize {
+        match self.format {
+            PpmFormat::RawBitmap => {
+                let row_bytes = ((self.width as usize) + 7) / 8;
+                row_bytes * (self.height as usize)
+            },
+            _ => {
+                let bytes_per_sample = if self.depth < 256 { 1 } else { 2 };
+                self.sample_count() * bytes_per_sample
+            },
+        }
+    }
+}
+
 
-    if &header[0..2] != b"P3" {
+/// Parses the magic, width, height, and (where present) maxval, handing
+/// back both the header and the byte reader positioned right after it so
+/// the raster can be decoded without re-parsing any of this.
+fn parse_header<R>(reader: R) -> PpmLoadResult<(PpmHeader, parse::ByteReader<R>)>
+    where R: Read {
+
+    let mut reader = parse::ByteReader::new(reader);
+
+    let magic = try!(reader.next_byte_required());
+    let format_byte = try!(reader.next_byte_required());
+    if magic != b'P' {
         return Err(PpmLoadError::FormatError);
     }
-    if !parse::is_whitespace(header[2]) {
+    let separator = try!(reader.next_byte_required());
+    if !parse::is_whitespace(separator) {
         return Err(PpmLoadError::FormatError);
     }
 
-    let mut values = parse::PpmChannelValues::new(reader.bytes().peekable());
+    let format = match format_byte {
+        b'3' => PpmFormat::AsciiColor,
+        b'6' => PpmFormat::RawColor,
+        b'5' => PpmFormat::RawGray,
+        b'4' => PpmFormat::RawBitmap,
+        _ => return Err(PpmLoadError::FormatError),
+    };
+
+    let mut values = parse::PpmChannelValues::new(reader);
     let width: u32 = try!(values.next().unwrap_or(Err(PpmLoadError::Truncated)));
     let height: u32 = try!(values.next().unwrap_or(Err(PpmLoadError::Truncated)));
-    let depth: u32 = try!(values.next().unwrap_or(Err(PpmLoadError::Truncated)));
-    if width == 0 || height == 0 || depth == 0 {
+    if width == 0 || height == 0 {
         return Err(PpmLoadError::FormatError);
     }
 
-    let mut pixels = helpers::chunks(values)
-        .map(|triple_res| triple_res.map(|triple| PpmPixel(triple[0], triple[1], triple[2])));
-        
-    FromPpm::from_ppm(width, height, depth, &mut pixels)
+    let depth: u32 = if format == PpmFormat::RawBitmap {
+        1
+    } else {
+        let depth: u32 = try!(values.next().unwrap_or(Err(PpmLoadError::Truncated)));
+        if depth == 0 {
+            return Err(PpmLoadError::FormatError);
+        }
+        depth
+    };
+
+    let header = PpmHeader {
+        format: format,
+        width: width,
+        height: height,
+        depth: depth,
+    };
+    Ok((header, values.into_inner()))
+}
+
+
+/// Parses just the header — magic, width, height, maxval — without reading
+/// any pixel data. Lets a caller size a buffer with `required_bytes()` or
+/// `sample_count()` before committing to a read of the raster itself.
+pub fn read_header<R>(reader: R) -> PpmLoadResult<PpmHeader>
+    where R: Read {
+
+    parse_header(reader).map(|(header, _)| header)
+}
+
+
+pub fn read_ppm<R, T>(reader: R) -> Result<T, PpmLoadError>
+    where
+        R: Read,
+        T: FromPpm {
+
+    let (header, reader) = try!(parse_header(reader));
+    let channels = header.channels();
+
+    match header.format {
+        PpmFormat::AsciiColor => {
+            let values = helpers::bounded(parse::PpmChannelValues::new(reader), header.depth);
+            let mut pixels = helpers::chunks(values, channels);
+            FromPpm::from_ppm(header.width, header.height, header.depth, channels, &mut pixels)
+        },
+        PpmFormat::RawColor | PpmFormat::RawGray => {
+            let mut pixels = parse::RawSamplePixels::new(
+                reader, header.depth, channels, header.width, header.height);
+            FromPpm::from_ppm(header.width, header.height, header.depth, channels, &mut pixels)
+        },
+        PpmFormat::RawBitmap => {
+            let mut pixels = parse::RawBitmapPixels::new(reader, header.width, header.height);
+            FromPpm::from_ppm(header.width, header.height, header.depth, channels, &mut pixels)
+        },
+    }
+}
+
+
+/// Like `read_ppm`, but rescales every sample from the file's declared
+/// maxval to `target_depth` (`sample * target_depth / depth`) before
+/// handing pixels to `FromPpm`, so callers see consistently-scaled pixels
+/// no matter what depth the source file declared.
+pub fn read_ppm_scaled<R, T>(reader: R, target_depth: u32) -> Result<T, PpmLoadError>
+    where
+        R: Read,
+        T: FromPpm {
+
+    let (header, reader) = try!(parse_header(reader));
+    let channels = header.channels();
+    let depth = header.depth;
+
+    match header.format {
+        PpmFormat::AsciiColor => {
+            let values = helpers::bounded(parse::PpmChannelValues::new(reader), depth);
+            let mut pixels = helpers::rescale(helpers::chunks(values, channels), depth, target_depth);
+            FromPpm::from_ppm(header.width, header.height, target_depth, channels, &mut pixels)
+        },
+        PpmFormat::RawColor | PpmFormat::RawGray => {
+            let pixels = parse::RawSamplePixels::new(
+                reader, depth, channels, header.width, header.height);
+            let mut pixels = helpers::rescale(pixels, depth, target_depth);
+            FromPpm::from_ppm(header.width, header.height, target_depth, channels, &mut pixels)
+        },
+        PpmFormat::RawBitmap => {
+            let pixels = parse::RawBitmapPixels::new(reader, header.width, header.height);
+            let mut pixels = helpers::rescale(pixels, depth, target_depth);
+            FromPpm::from_ppm(header.width, header.height, target_depth, channels, &mut pixels)
+        },
+    }
+}
+
+
+/// Decodes straight into a caller-supplied sample buffer instead of
+/// allocating a `Vec<PpmPixel>` per pixel. `buffer` must hold at least
+/// `header.sample_count()` samples once the header is known; pass a buffer
+/// sized from a prior `read_header()` call (or a known upper bound) to
+/// avoid growing an allocation while decoding.
+pub fn read_ppm_into<R>(reader: R, buffer: &mut [Sample]) -> PpmLoadResult<PpmHeader>
+    where R: Read {
+
+    let (header, reader) = try!(parse_header(reader));
+    let sample_count = header.sample_count();
+    if buffer.len() < sample_count {
+        return Err(PpmLoadError::Truncated);
+    }
+    let buffer = &mut buffer[..sample_count];
+
+    match header.format {
+        PpmFormat::AsciiColor => {
+            let mut values = helpers::bounded(parse::PpmChannelValues::new(reader), header.depth);
+            for slot in buffer.iter_mut() {
+                *slot = try!(values.next().unwrap_or(Err(PpmLoadError::Truncated)));
+            }
+        },
+        PpmFormat::RawColor | PpmFormat::RawGray => {
+            let mut samples = parse::RawSampleStream::new(reader, header.depth);
+            for slot in buffer.iter_mut() {
+                *slot = try!(samples.next_sample());
+            }
+        },
+        PpmFormat::RawBitmap => {
+            let mut bits = parse::RawBitmapPixels::new(reader, header.width, header.height);
+            for slot in buffer.iter_mut() {
+                *slot = try!(bits.next_sample().unwrap_or(Err(PpmLoadError::Truncated)));
+            }
+        },
+    }
+
+    Ok(header)
 }
 
 
@@ -81,9 +298,19 @@ pub fn load_ppm<T, P>(path: P) -> Result<T, PpmLoadError>
 }
 
 
+pub fn save_ppm<T, P>(image: &T, path: P) -> io::Result<()>
+    where
+        T: ToPpm,
+        P: AsRef<Path> {
+
+    write_ppm(try!(File::create(path)), image)
+}
+
+
 #[cfg(test)]
 mod tests {
-    use super::{read_ppm, PpmPixel, PpmLoadResult, PpmLoadError, FromPpm};
+    use super::{read_ppm, read_header, read_ppm_into, read_ppm_scaled, PpmPixel, PpmLoadResult,
+                 PpmLoadError, PpmFormat, FromPpm};
     use std::io;
 
     struct MockImageType {
@@ -93,7 +320,7 @@ mod tests {
     }
 
     impl FromPpm for MockImageType {
-        fn from_ppm(width: u32, height: u32, _depth: u32,
+        fn from_ppm(width: u32, height: u32, _depth: u32, _channels: usize,
                     pixels: &mut Iterator<Item=PpmLoadResult<PpmPixel>>
                    ) -> PpmLoadResult<MockImageType> {
 
@@ -130,18 +357,180 @@ mod tests {
         let image: MockImageType = read_ppm(io::Cursor::new(&msg[..])).unwrap();
         assert_eq!(image.width, 3);
         assert_eq!(image.height, 4);
-        assert_eq!(image.pixels[0],  PpmPixel( 77, 240, 254));
-        assert_eq!(image.pixels[1],  PpmPixel( 44, 195,  39));
-        assert_eq!(image.pixels[2],  PpmPixel( 57,  85, 152));
-        assert_eq!(image.pixels[3],  PpmPixel( 80, 159, 188));
-        assert_eq!(image.pixels[4],  PpmPixel(164, 165, 253));
-        assert_eq!(image.pixels[5],  PpmPixel(161, 114, 242));
-        assert_eq!(image.pixels[6],  PpmPixel( 69,  63,  89));
-        assert_eq!(image.pixels[7],  PpmPixel( 33, 160, 214));
-        assert_eq!(image.pixels[8],  PpmPixel(196, 139,   2));
-        assert_eq!(image.pixels[9],  PpmPixel(159, 164,  51));
-        assert_eq!(image.pixels[10], PpmPixel(144,  70,  69));
-        assert_eq!(image.pixels[11], PpmPixel( 90,  55, 133));
+        assert_eq!(image.pixels[0],  PpmPixel::new(vec![ 77, 240, 254]));
+        assert_eq!(image.pixels[1],  PpmPixel::new(vec![ 44, 195,  39]));
+        assert_eq!(image.pixels[2],  PpmPixel::new(vec![ 57,  85, 152]));
+        assert_eq!(image.pixels[3],  PpmPixel::new(vec![ 80, 159, 188]));
+        assert_eq!(image.pixels[4],  PpmPixel::new(vec![164, 165, 253]));
+        assert_eq!(image.pixels[5],  PpmPixel::new(vec![161, 114, 242]));
+        assert_eq!(image.pixels[6],  PpmPixel::new(vec![ 69,  63,  89]));
+        assert_eq!(image.pixels[7],  PpmPixel::new(vec![ 33, 160, 214]));
+        assert_eq!(image.pixels[8],  PpmPixel::new(vec![196, 139,   2]));
+        assert_eq!(image.pixels[9],  PpmPixel::new(vec![159, 164,  51]));
+        assert_eq!(image.pixels[10], PpmPixel::new(vec![144,  70,  69]));
+        assert_eq!(image.pixels[11], PpmPixel::new(vec![ 90,  55, 133]));
+    }
+
+    #[test]
+    fn test_p6_mock_image() {
+        let mut msg = b"P6\n2 1 255\n".to_vec();
+        msg.extend_from_slice(&[10, 20, 30, 40, 50, 60]);
+
+        let image: MockImageType = read_ppm(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(image.width, 2);
+        assert_eq!(image.height, 1);
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![10, 20, 30]));
+        assert_eq!(image.pixels[1], PpmPixel::new(vec![40, 50, 60]));
+    }
+
+    #[test]
+    fn test_p6_mock_image_16bit() {
+        let mut msg = b"P6\n1 1 65535\n".to_vec();
+        msg.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+
+        let image: MockImageType = read_ppm(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![0x0102, 0x0304, 0x0506]));
+    }
+
+    #[test]
+    fn test_p5_mock_image() {
+        let mut msg = b"P5\n3 1 255\n".to_vec();
+        msg.extend_from_slice(&[10, 20, 30]);
+
+        let image: MockImageType = read_ppm(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![10]));
+        assert_eq!(image.pixels[1], PpmPixel::new(vec![20]));
+        assert_eq!(image.pixels[2], PpmPixel::new(vec![30]));
+    }
+
+    #[test]
+    fn test_p4_mock_image() {
+        // 10 columns, 2 rows: row bytes are padded to 2 bytes per row.
+        let mut msg = b"P4\n10 2\n".to_vec();
+        msg.extend_from_slice(&[0b10101010, 0b11000000]);
+        msg.extend_from_slice(&[0b01010101, 0b00000000]);
+
+        let image: MockImageType = read_ppm(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(image.pixels.len(), 20);
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![0]));
+        assert_eq!(image.pixels[1], PpmPixel::new(vec![1]));
+        assert_eq!(image.pixels[8], PpmPixel::new(vec![0]));
+        assert_eq!(image.pixels[9], PpmPixel::new(vec![0]));
+        assert_eq!(image.pixels[10], PpmPixel::new(vec![1]));
+        assert_eq!(image.pixels[11], PpmPixel::new(vec![0]));
+    }
+
+    #[test]
+    fn test_read_header_ascii() {
+        let msg = b"P3\n3 4 255\n77 240 254  44 195  39  57  85 152  80 159 188\n";
+        let header = read_header(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(header.format, PpmFormat::AsciiColor);
+        assert_eq!(header.width, 3);
+        assert_eq!(header.height, 4);
+        assert_eq!(header.depth, 255);
+        assert_eq!(header.channels(), 3);
+        assert_eq!(header.sample_count(), 36);
+    }
+
+    #[test]
+    fn test_read_header_raw_bitmap() {
+        let msg = b"P4\n10 2\n\0\0\0\0";
+        let header = read_header(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(header.format, PpmFormat::RawBitmap);
+        assert_eq!(header.width, 10);
+        assert_eq!(header.height, 2);
+        assert_eq!(header.depth, 1);
+        assert_eq!(header.channels(), 1);
+        assert_eq!(header.sample_count(), 20);
+        assert_eq!(header.required_bytes(), 4);
+    }
+
+    #[test]
+    fn test_read_header_raw_color_required_bytes() {
+        let msg = b"P6\n2 1 65535\n\0\0\0\0\0\0\0\0\0\0\0\0";
+        let header = read_header(io::Cursor::new(&msg[..])).unwrap();
+        assert_eq!(header.sample_count(), 6);
+        assert_eq!(header.required_bytes(), 12);
+    }
+
+    #[test]
+    fn test_read_ppm_into_ascii() {
+        let msg = b"P3\n2 1 255\n1 2 3 4 5 6\n";
+        let mut buffer = [0u32; 6];
+        let header = read_ppm_into(io::Cursor::new(&msg[..]), &mut buffer).unwrap();
+        assert_eq!(header.width, 2);
+        assert_eq!(header.height, 1);
+        assert_eq!(buffer, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_read_ppm_into_raw_bitmap() {
+        let mut msg = b"P4\n10 2\n".to_vec();
+        msg.extend_from_slice(&[0b10101010, 0b11000000]);
+        msg.extend_from_slice(&[0b01010101, 0b00000000]);
+
+        let mut buffer = [0u32; 20];
+        let header = read_ppm_into(io::Cursor::new(&msg[..]), &mut buffer).unwrap();
+        assert_eq!(header.format, PpmFormat::RawBitmap);
+        assert_eq!(&buffer[0..2], &[0, 1]);
+        assert_eq!(&buffer[8..12], &[0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_read_ppm_into_buffer_too_small() {
+        let msg = b"P3\n2 1 255\n1 2 3 4 5 6\n";
+        let mut buffer = [0u32; 3];
+        let res = read_ppm_into(io::Cursor::new(&msg[..]), &mut buffer);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_ascii_sample_over_depth_is_rejected() {
+        let msg = b"P3\n1 1 15\n16 0 0\n";
+        let res: Result<MockImageType, _> = read_ppm(io::Cursor::new(&msg[..]));
+        match res {
+            Err(PpmLoadError::FormatError) => {},
+            Err(_) => panic!("expected FormatError"),
+            Ok(_) => panic!("expected an error, got Ok"),
+        }
+    }
+
+    #[test]
+    fn test_raw_sample_over_depth_is_rejected() {
+        let mut msg = b"P6\n1 1 15\n".to_vec();
+        msg.extend_from_slice(&[16, 0, 0]);
+        let res: Result<MockImageType, _> = read_ppm(io::Cursor::new(&msg[..]));
+        match res {
+            Err(PpmLoadError::FormatError) => {},
+            Err(_) => panic!("expected FormatError"),
+            Ok(_) => panic!("expected an error, got Ok"),
+        }
+    }
+
+    #[test]
+    fn test_ascii_digit_overflow_is_rejected() {
+        let msg = b"P3\n1 1 99999999999\n0 0 0\n";
+        let res: Result<MockImageType, _> = read_ppm(io::Cursor::new(&msg[..]));
+        match res {
+            Err(PpmLoadError::OverflowError) => {},
+            Err(_) => panic!("expected OverflowError"),
+            Ok(_) => panic!("expected an error, got Ok"),
+        }
+    }
+
+    #[test]
+    fn test_read_ppm_scaled_upscales_to_16bit() {
+        let msg = b"P3\n1 1 255\n255 0 128\n";
+        let image: MockImageType = read_ppm_scaled(io::Cursor::new(&msg[..]), 65535).unwrap();
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![65535, 0, 32896]));
+    }
+
+    #[test]
+    fn test_read_ppm_scaled_downscales_to_8bit() {
+        let mut msg = b"P6\n1 1 65535\n".to_vec();
+        msg.extend_from_slice(&[0xFF, 0xFF, 0x00, 0x00, 0x80, 0x80]);
+        let image: MockImageType = read_ppm_scaled(io::Cursor::new(&msg[..]), 255).unwrap();
+        assert_eq!(image.pixels[0], PpmPixel::new(vec![255, 0, 128]));
     }
 
     #[test]
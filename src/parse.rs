@@ -1,12 +1,11 @@
 use std::io::{self, Read};
-use std::iter::Peekable;
 use std::iter::Iterator;
-use super::{PpmLoadResult, PpmLoadError};
+use super::{PpmLoadResult, PpmLoadError, PpmPixel};
 
 
 #[inline(always)]
-fn is_whitespace(byte: u8) -> bool {
-    byte == b' ' || byte == b'\n'
+pub fn is_whitespace(byte: u8) -> bool {
+    byte == b' ' || byte == b'\n' || byte == b'\t' || byte == b'\r' || byte == b'\x0c'
 }
 
 
@@ -16,56 +15,136 @@ fn is_number(byte: u8) -> bool {
 }
 
 
-pub fn consume_whitespace<I>(peekable: &mut Peekable<I>) -> PpmLoadResult<()>
-    where
-        I: Iterator<Item=io::Result<u8>> {
+/// A small buffered byte reader sitting between the magic/header parser and
+/// the raw sample data. It supports peeking one byte ahead (used by
+/// `consume_whitespace` to stop in front of the next number without
+/// consuming it) and bulk reads straight from the underlying reader (used
+/// once we're past the header to pull packed binary samples: a whole row at
+/// a time for `P4`, one sample's one or two bytes at a time for `P5`/`P6`).
+pub struct ByteReader<R> where R: Read {
+    inner: R,
+    peeked: Option<u8>,
+}
 
-    loop {
-        match peekable.peek() {
-            Some(&Ok(byte)) if is_whitespace(byte) => peekable.next().unwrap().unwrap(),
-            Some(&Ok(byte)) if is_number(byte) => break,
-            Some(&Ok(_)) => return Err(PpmLoadError::FormatError),
-            Some(&Err(_)) => return Err(PpmLoadError::Io(peekable.next().unwrap().err().unwrap())),
-            None => return Ok(()),
-        };
+
+impl<R> ByteReader<R> where R: Read {
+    pub fn new(inner: R) -> ByteReader<R> {
+        ByteReader {
+            inner: inner,
+            peeked: None,
+        }
+    }
+
+    pub fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8; 1];
+            self.peeked = match try!(self.inner.read(&mut byte)) {
+                0 => return Ok(None),
+                _ => Some(byte[0]),
+            };
+        }
+        Ok(self.peeked)
+    }
+
+    pub fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0u8; 1];
+        match try!(self.inner.read(&mut byte)) {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    pub fn next_byte_required(&mut self) -> PpmLoadResult<u8> {
+        match try!(self.next_byte()) {
+            Some(byte) => Ok(byte),
+            None => Err(PpmLoadError::Truncated),
+        }
+    }
+
+    /// Bulk-reads into `buf`, using any already-peeked byte first and then
+    /// reading straight from the underlying reader. Returns the number of
+    /// bytes actually filled, which is less than `buf.len()` only at EOF.
+    pub fn read_buf(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut filled = 0;
+        if let Some(byte) = self.peeked.take() {
+            buf[0] = byte;
+            filled = 1;
+        }
+        while filled < buf.len() {
+            let read = try!(self.inner.read(&mut buf[filled..]));
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        Ok(filled)
+    }
+
+    pub fn read_exact_buf(&mut self, buf: &mut [u8]) -> PpmLoadResult<()> {
+        let filled = try!(self.read_buf(buf));
+        if filled != buf.len() {
+            return Err(PpmLoadError::Truncated);
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-pub fn read_number<I>(peekable: &mut Peekable<I>, buf: &mut String) -> PpmLoadResult<()>
-    where
-        I: Iterator<Item=io::Result<u8>> {
 
-    use std::char::from_u32;
+/// Consumes whitespace up to the next non-whitespace byte, leaving it
+/// peeked. A `#` starts a comment that runs to (and includes) the next
+/// `\n`, which the Netpbm spec allows anywhere whitespace is permitted in
+/// the header. Anything else that isn't whitespace and isn't the start of
+/// a number is a `FormatError`.
+pub fn consume_whitespace<R>(reader: &mut ByteReader<R>) -> PpmLoadResult<()>
+    where R: Read {
+
     loop {
-        match peekable.peek() {
-            Some(&Ok(byte)) if is_whitespace(byte) => break,
-            Some(&Ok(byte)) if is_number(byte) => {
-                let byte = peekable.next().unwrap().unwrap();
-                buf.push(from_u32(byte as u32).unwrap());
+        match try!(reader.peek()) {
+            Some(byte) if is_whitespace(byte) => { reader.next_byte().unwrap(); },
+            Some(b'#') => {
+                loop {
+                    match try!(reader.next_byte()) {
+                        Some(b'\n') | None => break,
+                        Some(_) => {},
+                    }
+                }
             },
-            Some(&Ok(_)) => return Err(PpmLoadError::FormatError),
-            Some(&Err(_)) => return Err(PpmLoadError::Io(peekable.next().unwrap().err().unwrap())),
+            Some(byte) if is_number(byte) => break,
+            Some(_) => return Err(PpmLoadError::FormatError),
             None => return Ok(()),
-        };
+        }
     }
     Ok(())
 }
 
 
 pub struct PpmChannelValues<R> where R: Read {
-    bytes: Peekable<io::Bytes<R>>,
+    bytes: ByteReader<R>,
     is_finished: bool,
 }
 
 
 impl<R> PpmChannelValues<R> where R: Read {
-    pub fn new(bytes: Peekable<io::Bytes<R>>) -> PpmChannelValues<R> {
+    pub fn new(bytes: ByteReader<R>) -> PpmChannelValues<R> {
         PpmChannelValues {
             bytes: bytes,
             is_finished: false,
         }
     }
+
+    /// Hands back the underlying byte reader, positioned right after the
+    /// single whitespace byte that ended the last value this iterator
+    /// produced (that byte is consumed). Used to switch from ASCII header
+    /// parsing over to raw binary sample reading.
+    pub fn into_inner(self) -> ByteReader<R> {
+        self.bytes
+    }
 }
 
 
@@ -78,51 +157,194 @@ impl<R> Iterator for PpmChannelValues<R> where R: Read {
         }
 
         if let Err(err) = consume_whitespace(&mut self.bytes) {
+            self.is_finished = true;
             return Some(Err(err));
         }
 
         let mut output: u32 = 0;
         let mut emit_number = false;
         loop {
-            match self.bytes.next() {
-                Some(Ok(digit)) if is_number(digit) => {
+            match self.bytes.next_byte() {
+                Ok(Some(digit)) if is_number(digit) => {
                     emit_number |= true;
-                    output *= 10;
-                    output += (digit - b'0') as u32;
+                    let digit_value = (digit - b'0') as u32;
+                    output = match output.checked_mul(10).and_then(|v| v.checked_add(digit_value)) {
+                        Some(v) => v,
+                        None => {
+                            self.is_finished = true;
+                            return Some(Err(PpmLoadError::OverflowError));
+                        },
+                    };
                 },
-                Some(Ok(digit)) if is_whitespace(digit) => return Some(Ok(output)),
-                Some(Ok(_)) => {
+                Ok(Some(digit)) if is_whitespace(digit) => return Some(Ok(output)),
+                Ok(Some(_)) => {
                     self.is_finished = true;
                     return Some(Err(PpmLoadError::FormatError));
                 }
-                Some(Err(err)) => {
+                Err(err) => {
                     self.is_finished = true;
                     return Some(Err(PpmLoadError::Io(err)));
                 }
-                None if emit_number => return Some(Ok(output)),
-                None => return None
+                Ok(None) if emit_number => return Some(Ok(output)),
+                Ok(None) => return None,
+            }
+        }
+    }
+}
+
+
+/// A flat stream of raw samples for the `P6`/`P5` rawbits formats: one byte
+/// per sample when `depth < 256`, two bytes big-endian otherwise. Used
+/// directly by the buffer-filling decode path, and wrapped by
+/// `RawSamplePixels` to group samples into pixels.
+pub struct RawSampleStream<R> where R: Read {
+    reader: ByteReader<R>,
+    depth: u32,
+}
+
+
+impl<R> RawSampleStream<R> where R: Read {
+    pub fn new(reader: ByteReader<R>, depth: u32) -> RawSampleStream<R> {
+        RawSampleStream {
+            reader: reader,
+            depth: depth,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> PpmLoadResult<u32> {
+        let sample = if self.depth < 256 {
+            let mut buf = [0u8; 1];
+            try!(self.reader.read_exact_buf(&mut buf));
+            buf[0] as u32
+        } else {
+            let mut buf = [0u8; 2];
+            try!(self.reader.read_exact_buf(&mut buf));
+            ((buf[0] as u32) << 8) | (buf[1] as u32)
+        };
+        if sample > self.depth {
+            return Err(PpmLoadError::FormatError);
+        }
+        Ok(sample)
+    }
+}
+
+
+/// Raw samples for `P6` (RGB) and `P5` (grayscale) rawbits rasters, grouped
+/// into `channels`-sample pixels.
+pub struct RawSamplePixels<R> where R: Read {
+    samples: RawSampleStream<R>,
+    channels: usize,
+    remaining: u64,
+}
+
+
+impl<R> RawSamplePixels<R> where R: Read {
+    pub fn new(reader: ByteReader<R>, depth: u32, channels: usize, width: u32, height: u32) -> RawSamplePixels<R> {
+        RawSamplePixels {
+            samples: RawSampleStream::new(reader, depth),
+            channels: channels,
+            remaining: (width as u64) * (height as u64),
+        }
+    }
+}
+
+
+impl<R> Iterator for RawSamplePixels<R> where R: Read {
+    type Item = PpmLoadResult<PpmPixel>;
+
+    fn next(&mut self) -> Option<PpmLoadResult<PpmPixel>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let mut channels = Vec::with_capacity(self.channels);
+        for _ in 0..self.channels {
+            match self.samples.next_sample() {
+                Ok(value) => channels.push(value),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        Some(Ok(PpmPixel::new(channels)))
+    }
+}
+
+
+/// `P4` rawbits bitmap: rows are packed 8 pixels per byte, MSB first, `1`
+/// meaning black, each row padded up to a byte boundary.
+pub struct RawBitmapPixels<R> where R: Read {
+    reader: ByteReader<R>,
+    width: u32,
+    rows_remaining: u32,
+    row: Vec<u8>,
+    col: u32,
+}
+
+
+impl<R> RawBitmapPixels<R> where R: Read {
+    pub fn new(reader: ByteReader<R>, width: u32, height: u32) -> RawBitmapPixels<R> {
+        let row_bytes = ((width as usize) + 7) / 8;
+        RawBitmapPixels {
+            reader: reader,
+            width: width,
+            rows_remaining: height,
+            row: vec![0u8; row_bytes],
+            col: width,
+        }
+    }
+}
+
+
+impl<R> RawBitmapPixels<R> where R: Read {
+    pub fn next_sample(&mut self) -> Option<PpmLoadResult<u32>> {
+        if self.col == self.width {
+            if self.rows_remaining == 0 {
+                return None;
+            }
+            self.rows_remaining -= 1;
+            if let Err(err) = self.reader.read_exact_buf(&mut self.row) {
+                return Some(Err(err));
             }
+            self.col = 0;
         }
+
+        let byte = self.row[(self.col / 8) as usize];
+        let bit_offset = 7 - (self.col % 8);
+        let bit = (byte >> bit_offset) & 1;
+        self.col += 1;
+
+        let sample = if bit == 1 { 0 } else { 1 };
+        Some(Ok(sample))
     }
 }
 
 
+impl<R> Iterator for RawBitmapPixels<R> where R: Read {
+    type Item = PpmLoadResult<PpmPixel>;
+
+    fn next(&mut self) -> Option<PpmLoadResult<PpmPixel>> {
+        match self.next_sample() {
+            Some(Ok(sample)) => Some(Ok(PpmPixel::new(vec![sample]))),
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        }
+    }
+}
 
 
 #[cfg(test)]
 mod tests {
-    use std::io::{self, Read};
-    use super::PpmChannelValues;
+    use std::io;
+    use super::{PpmChannelValues, ByteReader};
 
-    pub fn ppm_channel_values<R: Read>(reader: R) -> PpmChannelValues<R> {
-        PpmChannelValues::new(reader.bytes().peekable())
+    pub fn ppm_channel_values(msg: &[u8]) -> PpmChannelValues<io::Cursor<&[u8]>> {
+        PpmChannelValues::new(ByteReader::new(io::Cursor::new(msg)))
     }
 
     #[test]
     fn test_p3() {
         let msg = b"\n 12 \n12  4444 44 4444 11 2 3 13  \n  44 \n\n4\n1\n";
-        let reader = io::Cursor::new(&msg[..]);
-        let values: Vec<_> = ppm_channel_values(reader).map(|v| v.unwrap()).collect();
+        let values: Vec<_> = ppm_channel_values(&msg[..]).map(|v| v.unwrap()).collect();
 
         assert_eq!(values[0], 12);
         assert_eq!(values[1], 12);
@@ -138,4 +360,14 @@ mod tests {
         assert_eq!(values[11], 1);
     }
 
+    #[test]
+    fn test_comments_and_tabs() {
+        let msg = b"# CREATOR: GIMP PNM Filter Version 1.1\n12\t13\r\n# trailing note\n14\n";
+        let values: Vec<_> = ppm_channel_values(&msg[..]).map(|v| v.unwrap()).collect();
+
+        assert_eq!(values[0], 12);
+        assert_eq!(values[1], 13);
+        assert_eq!(values[2], 14);
+    }
+
 }
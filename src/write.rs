@@ -0,0 +1,194 @@
+use std::io::{self, Write};
+use super::PpmPixel;
+
+
+/// The line width ASCII rasters are wrapped at, matching the 70-column
+/// convention followed by `pnmtoplainpnm` and friends.
+pub const DEFAULT_MAX_LINE_WIDTH: usize = 70;
+
+
+/// The symmetric counterpart to `FromPpm`: anything that can be described
+/// as a width, a height, a depth, and a stream of pixels can be written out
+/// as a PPM.
+pub trait ToPpm {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn depth(&self) -> u32;
+    fn pixels(&self) -> Box<Iterator<Item=PpmPixel>>;
+}
+
+
+/// Writes `image` as an ASCII (`P3`) PPM, wrapping sample tokens so that no
+/// output line exceeds `max_line_width` columns.
+pub fn write_ppm_ascii<W, T>(mut writer: W, image: &T, max_line_width: usize) -> io::Result<()>
+    where
+        W: Write,
+        T: ToPpm {
+
+    try!(write!(writer, "P3\n{} {}\n{}\n", image.width(), image.height(), image.depth()));
+
+    let mut line_width = 0;
+    for pixel in image.pixels() {
+        for &sample in pixel.channels() {
+            let token = sample.to_string();
+            if line_width != 0 && line_width + 1 + token.len() > max_line_width {
+                try!(write!(writer, "\n"));
+                line_width = 0;
+            }
+            if line_width != 0 {
+                try!(write!(writer, " "));
+                line_width += 1;
+            }
+            try!(write!(writer, "{}", token));
+            line_width += token.len();
+        }
+    }
+    try!(write!(writer, "\n"));
+    Ok(())
+}
+
+
+/// Writes `image` as a binary (`P6`) rawbits PPM: one byte per sample when
+/// `depth < 256`, two bytes big-endian otherwise.
+pub fn write_ppm_raw<W, T>(mut writer: W, image: &T) -> io::Result<()>
+    where
+        W: Write,
+        T: ToPpm {
+
+    let depth = image.depth();
+    try!(write!(writer, "P6\n{} {}\n{}\n", image.width(), image.height(), depth));
+
+    for pixel in image.pixels() {
+        for &sample in pixel.channels() {
+            if depth < 256 {
+                try!(writer.write_all(&[sample as u8]));
+            } else {
+                try!(writer.write_all(&[(sample >> 8) as u8, sample as u8]));
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// Writes `image` as an ASCII `P3` PPM using the default 70-column wrap.
+pub fn write_ppm<W, T>(writer: W, image: &T) -> io::Result<()>
+    where
+        W: Write,
+        T: ToPpm {
+
+    write_ppm_ascii(writer, image, DEFAULT_MAX_LINE_WIDTH)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{write_ppm, write_ppm_ascii, write_ppm_raw, ToPpm};
+    use super::super::{read_ppm, FromPpm, PpmLoadResult, PpmPixel};
+
+    struct MockImage {
+        width: u32,
+        height: u32,
+        depth: u32,
+        pixels: Vec<PpmPixel>,
+    }
+
+    impl ToPpm for MockImage {
+        fn width(&self) -> u32 { self.width }
+        fn height(&self) -> u32 { self.height }
+        fn depth(&self) -> u32 { self.depth }
+        fn pixels(&self) -> Box<Iterator<Item=PpmPixel>> {
+            Box::new(self.pixels.clone().into_iter())
+        }
+    }
+
+    impl FromPpm for MockImage {
+        fn from_ppm(width: u32, height: u32, depth: u32, _channels: usize,
+                    pixels: &mut Iterator<Item=PpmLoadResult<PpmPixel>>
+                   ) -> PpmLoadResult<MockImage> {
+
+            let mut pixel_buf = Vec::with_capacity((width * height) as usize);
+            for pixel in pixels {
+                pixel_buf.push(try!(pixel));
+            }
+            Ok(MockImage {
+                width: width,
+                height: height,
+                depth: depth,
+                pixels: pixel_buf,
+            })
+        }
+    }
+
+    #[test]
+    fn test_write_ppm_ascii() {
+        let image = MockImage {
+            width: 2,
+            height: 1,
+            depth: 255,
+            pixels: vec![PpmPixel::new(vec![1, 2, 3]), PpmPixel::new(vec![4, 5, 6])],
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        write_ppm(&mut out, &image).unwrap();
+
+        assert_eq!(out.into_inner(), b"P3\n2 1\n255\n1 2 3 4 5 6\n".to_vec());
+    }
+
+    #[test]
+    fn test_write_ppm_ascii_wraps_long_lines() {
+        let image = MockImage {
+            width: 4,
+            height: 1,
+            depth: 255,
+            pixels: vec![
+                PpmPixel::new(vec![100, 100, 100]),
+                PpmPixel::new(vec![100, 100, 100]),
+                PpmPixel::new(vec![100, 100, 100]),
+                PpmPixel::new(vec![100, 100, 100]),
+            ],
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        write_ppm_ascii(&mut out, &image, 20).unwrap();
+
+        let text = String::from_utf8(out.into_inner()).unwrap();
+        for line in text.lines() {
+            assert!(line.len() <= 20);
+        }
+    }
+
+    #[test]
+    fn test_write_ppm_raw() {
+        let image = MockImage {
+            width: 2,
+            height: 1,
+            depth: 255,
+            pixels: vec![PpmPixel::new(vec![1, 2, 3]), PpmPixel::new(vec![4, 5, 6])],
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        write_ppm_raw(&mut out, &image).unwrap();
+
+        assert_eq!(out.into_inner(), b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06".to_vec());
+    }
+
+    #[test]
+    fn test_round_trip_ascii() {
+        let image = MockImage {
+            width: 2,
+            height: 1,
+            depth: 255,
+            pixels: vec![PpmPixel::new(vec![1, 2, 3]), PpmPixel::new(vec![4, 5, 6])],
+        };
+
+        let mut out = Cursor::new(Vec::new());
+        write_ppm(&mut out, &image).unwrap();
+
+        let reloaded: MockImage = read_ppm(Cursor::new(out.into_inner())).unwrap();
+        assert_eq!(reloaded.width, image.width);
+        assert_eq!(reloaded.height, image.height);
+        assert_eq!(reloaded.pixels, image.pixels);
+    }
+}